@@ -0,0 +1,57 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2022 The Moonfire NVR Authors; see AUTHORS and LICENSE.txt.
+// SPDX-License-Identifier: GPL-v3.0-or-later WITH GPL-3.0-linking-exception.
+
+//! Single-use, time-limited invitation tokens for self-service account setup.
+//!
+//! Mirrors how session ids are handled: the raw token is handed to the invitee (e.g. in an
+//! emailed link) and never stored; only its hash lives in the `pending_invite` column, so a
+//! leaked database dump doesn't let an attacker redeem outstanding invites.
+
+use ring::{constant_time, digest};
+
+/// How long an invite remains redeemable after creation.
+pub const VALIDITY_SEC: i64 = 7 * 24 * 3600;
+
+/// A freshly-minted invite: `token` goes to the invitee, `hash` is what gets stored.
+pub struct Invite {
+    pub token: String,
+    pub hash: Vec<u8>,
+}
+
+/// Mints a new random invite token and its storage hash.
+pub fn new() -> Invite {
+    let mut raw = [0u8; 24];
+    getrandom::getrandom(&mut raw).expect("getrandom failed");
+    let token = base64::encode_config(raw, base64::URL_SAFE_NO_PAD);
+    let hash = hash(&token);
+    Invite { token, hash }
+}
+
+/// Hashes a candidate token for comparison against a stored `pending_invite` hash.
+pub fn hash(token: &str) -> Vec<u8> {
+    digest::digest(&digest::SHA256, token.as_bytes())
+        .as_ref()
+        .to_vec()
+}
+
+/// Compares a candidate hash against a stored one in constant time, so that scanning
+/// `pending_invite`s for a match (the redeemed token is attacker-controlled) can't leak
+/// position-of-first-mismatch through response latency.
+pub fn hashes_match(stored: &[u8], candidate: &[u8]) -> bool {
+    constant_time::verify_slices_are_equal(stored, candidate).is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hash_is_deterministic_and_token_specific() {
+        let a = new();
+        assert_eq!(hash(&a.token), a.hash);
+        let b = new();
+        assert_ne!(a.token, b.token);
+        assert_ne!(a.hash, b.hash);
+    }
+}
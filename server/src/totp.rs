@@ -0,0 +1,131 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2022 The Moonfire NVR Authors; see AUTHORS and LICENSE.txt.
+// SPDX-License-Identifier: GPL-v3.0-or-later WITH GPL-3.0-linking-exception.
+
+//! TOTP (RFC 6238) two-factor authentication codes.
+//!
+//! Used by `web::users` to let a user enroll an authenticator app and require a second
+//! factor on top of their password at login.
+
+use hmac::{Hmac, Mac};
+use sha1::Sha1;
+
+/// Start of the TOTP epoch, per RFC 6238 `T0`.
+const T0: u64 = 0;
+
+/// Time step in seconds, per RFC 6238 `X`.
+const PERIOD: u64 = 30;
+
+/// Number of steps of clock skew to tolerate on either side of the current time.
+const SKEW_STEPS: i64 = 1;
+
+/// Returns the counter `T = floor((unix_seconds - T0) / period)` for `unix_sec`.
+fn counter(unix_sec: i64) -> u64 {
+    ((unix_sec as u64).saturating_sub(T0)) / PERIOD
+}
+
+/// Computes the 6-digit TOTP code for `secret` at counter `t`, per RFC 4226/6238.
+fn generate(secret: &[u8], t: u64) -> u32 {
+    let mut mac = Hmac::<Sha1>::new_from_slice(secret).expect("HMAC accepts any key length");
+    mac.update(&t.to_be_bytes());
+    let hash = mac.finalize().into_bytes();
+    let offset = (hash[hash.len() - 1] & 0xf) as usize;
+    let bin_code = (u32::from(hash[offset] & 0x7f) << 24)
+        | (u32::from(hash[offset + 1]) << 16)
+        | (u32::from(hash[offset + 2]) << 8)
+        | u32::from(hash[offset + 3]);
+    bin_code % 1_000_000
+}
+
+/// Verifies `code` against `secret` at time `unix_sec`, tolerating `SKEW_STEPS` steps of
+/// clock skew on either side and rejecting replays of a previously-accepted counter.
+///
+/// On success, returns the counter that was accepted; the caller should persist this as the
+/// user's new "last accepted counter" to block replay within the window.
+pub fn verify(secret: &[u8], code: u32, unix_sec: i64, last_accepted: Option<u64>) -> Option<u64> {
+    let t = counter(unix_sec);
+    for delta in -SKEW_STEPS..=SKEW_STEPS {
+        let candidate = match delta {
+            d if d < 0 => t.checked_sub((-d) as u64),
+            d => t.checked_add(d as u64),
+        }?;
+        if last_accepted.map_or(false, |last| candidate <= last) {
+            continue;
+        }
+        if generate(secret, candidate) == code {
+            return Some(candidate);
+        }
+    }
+    None
+}
+
+/// Generates a random 160-bit (20-byte) shared secret suitable for a new enrollment.
+pub fn generate_secret() -> [u8; 20] {
+    let mut secret = [0u8; 20];
+    getrandom::getrandom(&mut secret).expect("getrandom failed");
+    secret
+}
+
+/// Builds the `otpauth://totp/...` provisioning URI for enrollment, e.g. to render as a QR
+/// code. `issuer` and `account_name` are displayed by the authenticator app.
+pub fn provisioning_uri(issuer: &str, account_name: &str, secret: &[u8]) -> String {
+    let secret = base32_encode(secret);
+    format!(
+        "otpauth://totp/{issuer}:{account_name}?secret={secret}&issuer={issuer}&algorithm=SHA1&digits=6&period={PERIOD}",
+        issuer = urlencoding::encode(issuer),
+        account_name = urlencoding::encode(account_name),
+    )
+}
+
+/// Encodes `data` as unpadded base32 (RFC 4648), as used in `otpauth://` secrets.
+fn base32_encode(data: &[u8]) -> String {
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+    let mut out = String::with_capacity((data.len() * 8 + 4) / 5);
+    let mut buf: u32 = 0;
+    let mut bits = 0u32;
+    for &b in data {
+        buf = (buf << 8) | u32::from(b);
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            out.push(ALPHABET[((buf >> bits) & 0x1f) as usize] as char);
+        }
+    }
+    if bits > 0 {
+        out.push(ALPHABET[((buf << (5 - bits)) & 0x1f) as usize] as char);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// RFC 6238 test vector: SHA1, 20-byte secret "12345678901234567890", T=59s -> 94287082.
+    #[test]
+    fn rfc6238_test_vector() {
+        let secret = b"12345678901234567890";
+        assert_eq!(generate(secret, counter(59)), 94287082 % 1_000_000);
+    }
+
+    #[test]
+    fn verify_rejects_replay() {
+        let secret = generate_secret();
+        let code = generate(&secret, counter(1_000_000_000));
+        let accepted = verify(&secret, code, 1_000_000_000, None).unwrap();
+        assert_eq!(verify(&secret, code, 1_000_000_000, Some(accepted)), None);
+    }
+
+    #[test]
+    fn verify_tolerates_clock_skew() {
+        let secret = generate_secret();
+        let t = counter(1_000_000_000);
+        let code = generate(&secret, t + 1);
+        assert!(verify(&secret, code, 1_000_000_000, None).is_some());
+    }
+
+    #[test]
+    fn base32_encodes_known_vector() {
+        assert_eq!(base32_encode(b"12345678901234567890"), "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ");
+    }
+}
@@ -4,6 +4,7 @@
 
 //! Unified connection handling for TCP and Unix sockets.
 
+use std::os::unix::io::{FromRawFd, RawFd};
 use std::pin::Pin;
 
 pub enum Listener {
@@ -12,6 +13,60 @@ pub enum Listener {
 }
 
 impl Listener {
+    /// Adopts file descriptors passed by systemd via the `LISTEN_FDS`/`LISTEN_PID` protocol.
+    ///
+    /// Each inherited fd is wrapped as a [`Listener::Tcp`] or [`Listener::Unix`] depending on
+    /// the socket's address family, as reported by `getsockname`. Returns an empty `Vec` if
+    /// this process wasn't started with socket activation (no `LISTEN_FDS` for our pid).
+    ///
+    /// See `sd_listen_fds(3)` for the protocol this implements.
+    pub fn from_systemd() -> std::io::Result<Vec<Listener>> {
+        let n = match systemd_listen_fds()? {
+            Some(n) => n,
+            None => return Ok(Vec::new()),
+        };
+        (0..n)
+            .map(|i| {
+                let fd: RawFd = 3 + i;
+                // SAFETY: fd is owned by this process per the LISTEN_FDS contract above, and
+                // each fd is wrapped exactly once.
+                Self::from_systemd_fd(fd)
+            })
+            .collect()
+    }
+
+    fn from_systemd_fd(fd: RawFd) -> std::io::Result<Listener> {
+        use nix::sys::socket::{getsockname, SockaddrStorage};
+        let addr: SockaddrStorage = getsockname(fd)?;
+        if addr.as_unix_addr().is_some() {
+            let std_l = unsafe { std::os::unix::net::UnixListener::from_raw_fd(fd) };
+            std_l.set_nonblocking(true)?;
+            Ok(Listener::Unix(tokio::net::UnixListener::from_std(std_l)?))
+        } else {
+            let std_l = unsafe { std::net::TcpListener::from_raw_fd(fd) };
+            std_l.set_nonblocking(true)?;
+            Ok(Listener::Tcp(tokio::net::TcpListener::from_std(std_l)?))
+        }
+    }
+
+    /// Tells the service manager the daemon is ready to accept connections.
+    ///
+    /// No-op (and not an error) when not running under a service manager that supports
+    /// `sd_notify`, e.g. outside of `Type=notify` systemd units.
+    pub fn notify_ready() -> std::io::Result<()> {
+        sd_notify::notify(false, &[sd_notify::NotifyState::Ready]).map_err(to_io_error)
+    }
+
+    /// Tells the service manager the daemon is shutting down.
+    pub fn notify_stopping() -> std::io::Result<()> {
+        sd_notify::notify(true, &[sd_notify::NotifyState::Stopping]).map_err(to_io_error)
+    }
+
+    /// Pings the service manager's watchdog, if `WatchdogSec=` is configured for this unit.
+    pub fn notify_watchdog() -> std::io::Result<()> {
+        sd_notify::notify(false, &[sd_notify::NotifyState::Watchdog]).map_err(to_io_error)
+    }
+
     pub async fn accept(&mut self) -> std::io::Result<Conn> {
         match self {
             Listener::Tcp(l) => {
@@ -23,6 +78,7 @@ impl Listener {
                         client_unix_uid: None,
                         client_addr: Some(a),
                     },
+                    count_guard: None,
                 })
             }
             Listener::Unix(l) => {
@@ -34,16 +90,75 @@ impl Listener {
                         client_unix_uid: Some(nix::unistd::Uid::from_raw(ucred.uid())),
                         client_addr: None,
                     },
+                    count_guard: None,
                 })
             }
         }
     }
+
+    /// Like [`Self::accept`], but tracks the accepted connection in `counts` for the lifetime
+    /// of the returned [`Conn`] (decremented on drop). Used so `/api/diagnostics` can report
+    /// live connection counts split by socket type.
+    pub async fn accept_counted(&mut self, counts: std::sync::Arc<ConnCounts>) -> std::io::Result<Conn> {
+        let mut conn = self.accept().await?;
+        let is_unix = conn.data.client_unix_uid.is_some();
+        counts.inc(is_unix);
+        conn.count_guard = Some(ConnCountGuard { counts, is_unix });
+        Ok(conn)
+    }
+}
+
+/// Live counts of open connections, split by socket type, for `/api/diagnostics`.
+#[derive(Default)]
+pub struct ConnCounts {
+    tcp: std::sync::atomic::AtomicUsize,
+    unix: std::sync::atomic::AtomicUsize,
+}
+
+impl ConnCounts {
+    fn inc(&self, is_unix: bool) {
+        self.counter(is_unix)
+            .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+    }
+
+    fn dec(&self, is_unix: bool) {
+        self.counter(is_unix)
+            .fetch_sub(1, std::sync::atomic::Ordering::Relaxed);
+    }
+
+    fn counter(&self, is_unix: bool) -> &std::sync::atomic::AtomicUsize {
+        if is_unix {
+            &self.unix
+        } else {
+            &self.tcp
+        }
+    }
+
+    /// Returns `(tcp, unix)` connection counts as of now.
+    pub fn snapshot(&self) -> (usize, usize) {
+        (
+            self.tcp.load(std::sync::atomic::Ordering::Relaxed),
+            self.unix.load(std::sync::atomic::Ordering::Relaxed),
+        )
+    }
+}
+
+struct ConnCountGuard {
+    counts: std::sync::Arc<ConnCounts>,
+    is_unix: bool,
+}
+
+impl Drop for ConnCountGuard {
+    fn drop(&mut self) {
+        self.counts.dec(self.is_unix);
+    }
 }
 
 /// An open connection.
 pub struct Conn {
     stream: Stream,
     data: ConnData,
+    count_guard: Option<ConnCountGuard>,
 }
 
 /// Extra data associated with a connection.
@@ -113,3 +228,30 @@ enum Stream {
     Tcp(tokio::net::TcpStream),
     Unix(tokio::net::UnixStream),
 }
+
+/// Returns the number of fds passed via `LISTEN_FDS`, or `None` if this process isn't the
+/// intended recipient (`LISTEN_PID` doesn't match our pid) or socket activation wasn't used.
+fn systemd_listen_fds() -> std::io::Result<Option<RawFd>> {
+    let pid = match std::env::var("LISTEN_PID") {
+        Ok(p) => p,
+        Err(_) => return Ok(None),
+    };
+    let pid: u32 = pid
+        .parse()
+        .map_err(|_| std::io::Error::new(std::io::ErrorKind::InvalidInput, "bad LISTEN_PID"))?;
+    if pid != std::process::id() {
+        return Ok(None);
+    }
+    let fds = match std::env::var("LISTEN_FDS") {
+        Ok(f) => f,
+        Err(_) => return Ok(None),
+    };
+    let fds: RawFd = fds
+        .parse()
+        .map_err(|_| std::io::Error::new(std::io::ErrorKind::InvalidInput, "bad LISTEN_FDS"))?;
+    Ok(Some(fds))
+}
+
+fn to_io_error<E: std::error::Error + Send + Sync + 'static>(e: E) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::Other, e)
+}
@@ -0,0 +1,161 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2022 The Moonfire NVR Authors; see AUTHORS and LICENSE.txt.
+// SPDX-License-Identifier: GPL-v3.0-or-later WITH GPL-3.0-linking-exception.
+
+//! Pluggable authentication backends.
+//!
+//! Identity verification for `web::users` and every other permission-gated handler is
+//! abstracted behind [`ApiAuth`] rather than hard-wired to the cookie/session store, so
+//! deployments that front Moonfire with an SSO proxy or authenticate purely by Unix peer
+//! credentials don't need to touch the handlers themselves.
+
+use std::sync::Arc;
+
+use http::HeaderMap;
+
+use super::accept::ConnData;
+use super::Caller;
+
+/// Resolves the [`Caller`] for a request, given the connection it arrived on and its headers.
+///
+/// Implementations should treat failure to authenticate as `Caller::anonymous()` rather than
+/// an error where the underlying protocol allows it (matching the existing cookie-session
+/// behavior of falling back to anonymous permissions), reserving `Err` for malformed input
+/// (e.g. a corrupt cookie) that the caller may want to log distinctly.
+pub trait ApiAuth: Send + Sync {
+    fn authenticate(&self, conn: &ConnData, headers: &HeaderMap) -> Result<Caller, base::Error>;
+}
+
+/// The default backend: resolves the caller from the `s=<session id>` cookie, as Moonfire has
+/// always done.
+pub struct CookieSessionAuth {
+    db: Arc<db::Database<base::clock::RealClocks>>,
+}
+
+impl CookieSessionAuth {
+    pub fn new(db: Arc<db::Database<base::clock::RealClocks>>) -> Self {
+        Self { db }
+    }
+}
+
+impl ApiAuth for CookieSessionAuth {
+    fn authenticate(&self, _conn: &ConnData, headers: &HeaderMap) -> Result<Caller, base::Error> {
+        let sid = headers
+            .get(http::header::COOKIE)
+            .and_then(|c| c.to_str().ok())
+            .and_then(|c| find_cookie(c, "s"));
+        let sid = match sid {
+            Some(s) => s,
+            None => return Ok(Caller::anonymous()),
+        };
+        let mut raw = [0u8; 48];
+        let n = base64::decode_config_slice(sid, base64::STANDARD_NO_PAD, &mut raw)
+            .map_err(|e| base::err!(InvalidArgument, msg("invalid session cookie: {e}")))?;
+        let l = self.db.lock();
+        l.caller_from_session(&raw[..n])
+    }
+}
+
+/// Maps a configurable reverse-proxy header (set by an authenticated, trusted upstream, e.g.
+/// an SSO proxy terminating auth in front of Moonfire) to an existing Moonfire user, with no
+/// password or session involved. Only appropriate when the network path from the proxy to this
+/// process is trusted; Moonfire has no way to verify the header wasn't forged by some other
+/// client.
+pub struct TrustedHeaderAuth {
+    header_name: http::HeaderName,
+    db: Arc<db::Database<base::clock::RealClocks>>,
+}
+
+impl TrustedHeaderAuth {
+    pub fn new(
+        header_name: http::HeaderName,
+        db: Arc<db::Database<base::clock::RealClocks>>,
+    ) -> Self {
+        Self { header_name, db }
+    }
+}
+
+impl ApiAuth for TrustedHeaderAuth {
+    fn authenticate(&self, _conn: &ConnData, headers: &HeaderMap) -> Result<Caller, base::Error> {
+        let username = match headers.get(&self.header_name).and_then(|v| v.to_str().ok()) {
+            Some(u) if !u.is_empty() => u,
+            _ => return Ok(Caller::anonymous()),
+        };
+        let l = self.db.lock();
+        let user = l
+            .get_user(username)
+            .ok_or_else(|| base::err!(Unauthenticated, msg("no such user {username:?}")))?;
+        if user.config.disabled {
+            return Err(base::err!(Unauthenticated, msg("user is disabled")));
+        }
+        Ok(Caller {
+            user: Some(user.clone()),
+            permissions: user.permissions.clone(),
+        })
+    }
+}
+
+/// Authenticates purely via the peer's Unix uid, captured on Unix-socket connections (see
+/// [`ConnData::client_unix_uid`]). Lets local admin tooling (e.g. a `systemctl`-managed sidecar
+/// running as a dedicated uid) authenticate without any credential exchange at all.
+pub struct UnixPeerCredentialAuth {
+    /// Maps a peer uid to the Moonfire username it authenticates as.
+    uid_to_username: std::collections::HashMap<u32, String>,
+    db: Arc<db::Database<base::clock::RealClocks>>,
+}
+
+impl UnixPeerCredentialAuth {
+    pub fn new(
+        uid_to_username: std::collections::HashMap<u32, String>,
+        db: Arc<db::Database<base::clock::RealClocks>>,
+    ) -> Self {
+        Self {
+            uid_to_username,
+            db,
+        }
+    }
+}
+
+impl ApiAuth for UnixPeerCredentialAuth {
+    fn authenticate(&self, conn: &ConnData, _headers: &HeaderMap) -> Result<Caller, base::Error> {
+        let uid = match conn.client_unix_uid {
+            Some(uid) => uid.as_raw(),
+            None => return Ok(Caller::anonymous()),
+        };
+        let username = match self.uid_to_username.get(&uid) {
+            Some(u) => u,
+            None => return Ok(Caller::anonymous()),
+        };
+        let l = self.db.lock();
+        let user = l
+            .get_user(username)
+            .ok_or_else(|| base::err!(Unauthenticated, msg("no such user {username:?}")))?;
+        if user.config.disabled {
+            return Err(base::err!(Unauthenticated, msg("user is disabled")));
+        }
+        Ok(Caller {
+            user: Some(user.clone()),
+            permissions: user.permissions.clone(),
+        })
+    }
+}
+
+/// Finds `name=value` in a `Cookie` header's `;`-separated list, returning `value`.
+fn find_cookie<'a>(cookie_header: &'a str, name: &str) -> Option<&'a str> {
+    cookie_header.split(';').find_map(|kv| {
+        let kv = kv.trim();
+        let (k, v) = kv.split_once('=')?;
+        (k == name).then_some(v)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn find_cookie_picks_matching_pair() {
+        assert_eq!(find_cookie("a=1; s=abc; b=2", "s"), Some("abc"));
+        assert_eq!(find_cookie("a=1; b=2", "s"), None);
+    }
+}
@@ -0,0 +1,193 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2022 The Moonfire NVR Authors; see AUTHORS and LICENSE.txt.
+// SPDX-License-Identifier: GPL-v3.0-or-later WITH GPL-3.0-linking-exception.
+
+//! Negotiated `Content-Encoding` compression for JSON API responses.
+//!
+//! `serve_json` calls [`negotiate`] with the request's `Accept-Encoding` header and the
+//! serialized body, and if it returns an encoding, replaces the body with the compressed bytes
+//! and adds that encoding's `Content-Encoding` header. Left unused by endpoints that serve already-compressed media
+//! (recording segments), which should keep calling the uncompressed response path.
+
+use flate2::write::{DeflateEncoder, GzEncoder};
+use flate2::Compression;
+use http::HeaderValue;
+use std::io::Write as _;
+
+/// Bodies smaller than this aren't worth the CPU cost of compressing; gzip/deflate framing
+/// overhead can even make tiny bodies larger.
+pub const DEFAULT_MIN_BYTES: usize = 1024;
+
+/// `serve_json` should always send this header once negotiation has run, regardless of
+/// whether compression was applied, so caches don't serve a gzipped response to a client that
+/// didn't ask for one (or vice versa).
+pub const VARY_HEADER_VALUE: HeaderValue = HeaderValue::from_static("Accept-Encoding");
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Encoding {
+    Gzip,
+    Deflate,
+}
+
+impl Encoding {
+    /// The `Content-Encoding` header value to send alongside the compressed body.
+    pub fn content_encoding_header(self) -> HeaderValue {
+        HeaderValue::from_static(match self {
+            Encoding::Gzip => "gzip",
+            Encoding::Deflate => "deflate",
+        })
+    }
+}
+
+/// Server-configurable compression behavior; `level` trades CPU for bandwidth savings.
+#[derive(Copy, Clone, Debug)]
+pub struct Config {
+    pub min_bytes: usize,
+    pub level: Compression,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            min_bytes: DEFAULT_MIN_BYTES,
+            level: Compression::default(),
+        }
+    }
+}
+
+/// Picks a response encoding from the client's `Accept-Encoding` header, preferring gzip, and
+/// only when `body_len` clears `config.min_bytes`. Returns `None` if nothing should be applied,
+/// in which case the caller should send the body uncompressed and unmodified.
+pub fn negotiate(
+    accept_encoding: Option<&HeaderValue>,
+    body_len: usize,
+    config: &Config,
+) -> Option<Encoding> {
+    if body_len < config.min_bytes {
+        return None;
+    }
+    let accept_encoding = accept_encoding?.to_str().ok()?;
+    let offers: Vec<(&str, f32)> = accept_encoding.split(',').map(parse_offer).collect();
+    // A coding's own q-value, if explicitly listed, overrides `*` (RFC 7231 section 5.3.1) --
+    // e.g. `gzip;q=0, *` must not gzip, even though `*` alone would otherwise accept anything.
+    let is_acceptable = |name: &str| match offers.iter().find(|&&(o, _)| o == name) {
+        Some(&(_, q)) => q > 0.0,
+        None => offers.iter().any(|&(o, q)| o == "*" && q > 0.0),
+    };
+    if is_acceptable("gzip") {
+        Some(Encoding::Gzip)
+    } else if is_acceptable("deflate") {
+        Some(Encoding::Deflate)
+    } else {
+        None
+    }
+}
+
+/// Parses one comma-separated `Accept-Encoding` offer, e.g. `"gzip;q=0.5"`, into its coding
+/// name and `q`-value (defaulting to `1.0` when absent, per RFC 7231 section 5.3.1). A `q` of
+/// `0` explicitly forbids that coding, so callers must check it rather than just matching the
+/// name.
+fn parse_offer(offer: &str) -> (&str, f32) {
+    let mut parts = offer.split(';');
+    let name = parts.next().unwrap_or("").trim();
+    let q = parts
+        .find_map(|p| p.trim().strip_prefix("q="))
+        .and_then(|v| v.trim().parse::<f32>().ok())
+        .unwrap_or(1.0);
+    (name, q)
+}
+
+/// Compresses `body` with the given `encoding` and `level`. Only worth calling after
+/// [`negotiate`] has chosen to apply compression.
+pub fn compress(body: &[u8], encoding: Encoding, level: Compression) -> std::io::Result<Vec<u8>> {
+    match encoding {
+        Encoding::Gzip => {
+            let mut e = GzEncoder::new(Vec::new(), level);
+            e.write_all(body)?;
+            e.finish()
+        }
+        Encoding::Deflate => {
+            let mut e = DeflateEncoder::new(Vec::new(), level);
+            e.write_all(body)?;
+            e.finish()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn negotiate_respects_min_bytes() {
+        let config = Config {
+            min_bytes: 1024,
+            level: Compression::default(),
+        };
+        let accept = HeaderValue::from_static("gzip");
+        assert_eq!(negotiate(Some(&accept), 100, &config), None);
+        assert_eq!(
+            negotiate(Some(&accept), 2048, &config),
+            Some(Encoding::Gzip)
+        );
+    }
+
+    #[test]
+    fn negotiate_prefers_gzip_over_deflate() {
+        let config = Config::default();
+        let accept = HeaderValue::from_static("deflate, gzip");
+        assert_eq!(
+            negotiate(Some(&accept), config.min_bytes, &config),
+            Some(Encoding::Gzip)
+        );
+    }
+
+    #[test]
+    fn negotiate_falls_back_to_deflate() {
+        let config = Config::default();
+        let accept = HeaderValue::from_static("deflate");
+        assert_eq!(
+            negotiate(Some(&accept), config.min_bytes, &config),
+            Some(Encoding::Deflate)
+        );
+    }
+
+    #[test]
+    fn negotiate_none_when_unsupported() {
+        let config = Config::default();
+        let accept = HeaderValue::from_static("br");
+        assert_eq!(negotiate(Some(&accept), config.min_bytes, &config), None);
+    }
+
+    #[test]
+    fn negotiate_honors_q_zero() {
+        let config = Config::default();
+        let accept = HeaderValue::from_static("gzip;q=0, deflate");
+        assert_eq!(
+            negotiate(Some(&accept), config.min_bytes, &config),
+            Some(Encoding::Deflate)
+        );
+        let accept = HeaderValue::from_static("gzip;q=0");
+        assert_eq!(negotiate(Some(&accept), config.min_bytes, &config), None);
+    }
+
+    #[test]
+    fn negotiate_specific_q_zero_overrides_wildcard() {
+        let config = Config::default();
+        let accept = HeaderValue::from_static("gzip;q=0, *");
+        assert_eq!(
+            negotiate(Some(&accept), config.min_bytes, &config),
+            Some(Encoding::Deflate)
+        );
+    }
+
+    #[test]
+    fn compress_gzip_round_trips() {
+        let body = b"hello world".repeat(200);
+        let compressed = compress(&body, Encoding::Gzip, Compression::default()).unwrap();
+        let mut d = flate2::read::GzDecoder::new(&compressed[..]);
+        let mut out = Vec::new();
+        std::io::Read::read_to_end(&mut d, &mut out).unwrap();
+        assert_eq!(out, body);
+    }
+}
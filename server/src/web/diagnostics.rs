@@ -0,0 +1,150 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2022 The Moonfire NVR Authors; see AUTHORS and LICENSE.txt.
+// SPDX-License-Identifier: GPL-v3.0-or-later WITH GPL-3.0-linking-exception.
+
+//! Admin diagnostics: `/api/diagnostics`.
+//!
+//! A single JSON endpoint mirroring the "diagnostics" panel operators otherwise have to get by
+//! shelling into the box: versions, database integrity, per-sample-file-directory disk usage,
+//! live connection counts (from [`super::accept::ConnCounts`]), and session counts. Gated by
+//! the same `admin_users` permission as `get_users`, since it can reveal directory paths and
+//! other details useful to an attacker.
+
+use base::bail;
+use http::{Method, Request, StatusCode};
+use serde::Serialize;
+
+use super::{plain_response, Caller, ResponseResult, Service};
+
+impl Service {
+    pub(super) async fn diagnostics(
+        &self,
+        req: Request<hyper::body::Incoming>,
+        caller: Caller,
+    ) -> ResponseResult {
+        if !caller.permissions.admin_users {
+            bail!(Unauthenticated, msg("must have admin_users permission"));
+        }
+        match *req.method() {
+            Method::GET | Method::HEAD => {}
+            _ => {
+                return Ok(plain_response(
+                    StatusCode::METHOD_NOT_ALLOWED,
+                    "GET or HEAD expected",
+                ))
+            }
+        }
+        let report = self.build_diagnostics_report().await?;
+        let (parts, _) = req.into_parts();
+        self.serve_json(&parts, &report)
+    }
+
+    /// Builds the report, snapshotting the cheap fields under `self.db`'s lock first.
+    /// `quick_check` (a full SQLite scan) and the per-directory `statvfs`/write-test probes are
+    /// blocking I/O that can take seconds; both run in [`tokio::task::spawn_blocking`] after the
+    /// lock above is dropped, so they don't block the async runtime. `quick_check` takes the
+    /// database lock again for the whole of its scan, so other handlers waiting on the database
+    /// wait for it; the directory probes run without the lock.
+    async fn build_diagnostics_report(&self) -> Result<DiagnosticsReport, base::Error> {
+        let (moonfire_version, schema_version, sqlite_version, session_count, dirs) = {
+            let l = self.db.lock();
+            let dirs: Vec<_> = l
+                .sample_file_dirs_by_id()
+                .values()
+                .map(|d| d.path().to_owned())
+                .collect();
+            (
+                env!("CARGO_PKG_VERSION"),
+                l.schema_version(),
+                rusqlite::version(),
+                l.sessions_by_id().len(),
+                dirs,
+            )
+        };
+        let (tcp_conns, unix_conns) = self.conn_counts.snapshot();
+        let db = self.db.clone();
+        let (db_integrity_ok, sample_file_dirs) = tokio::task::spawn_blocking(move || {
+            let db_integrity_ok = db.lock().quick_check()?;
+            let sample_file_dirs = dirs
+                .iter()
+                .map(|p| SampleFileDirReport::new(p))
+                .collect::<Vec<_>>();
+            Ok::<_, base::Error>((db_integrity_ok, sample_file_dirs))
+        })
+        .await
+        .map_err(|e| base::err!(Internal, msg("diagnostics task panicked: {e}")))??;
+        Ok(DiagnosticsReport {
+            moonfire_version,
+            schema_version,
+            sqlite_version,
+            db_integrity_ok,
+            sample_file_dirs,
+            tcp_connections: tcp_conns,
+            unix_connections: unix_conns,
+            session_count,
+        })
+    }
+}
+
+#[derive(Serialize)]
+struct DiagnosticsReport {
+    moonfire_version: &'static str,
+    schema_version: i32,
+    sqlite_version: &'static str,
+    db_integrity_ok: bool,
+    sample_file_dirs: Vec<SampleFileDirReport>,
+    tcp_connections: usize,
+    unix_connections: usize,
+    session_count: usize,
+}
+
+#[derive(Serialize)]
+struct SampleFileDirReport {
+    path: std::path::PathBuf,
+    free_bytes: Option<u64>,
+    total_bytes: Option<u64>,
+    writable: bool,
+}
+
+impl SampleFileDirReport {
+    fn new(path: &std::path::Path) -> Self {
+        let (free_bytes, total_bytes) = statvfs_bytes(path).unwrap_or((None, None));
+        SampleFileDirReport {
+            path: path.to_owned(),
+            free_bytes,
+            total_bytes,
+            writable: write_test(path).is_ok(),
+        }
+    }
+}
+
+/// Returns `(free_bytes, total_bytes)` for the filesystem holding `path`, if it can be statted.
+fn statvfs_bytes(path: &std::path::Path) -> std::io::Result<(Option<u64>, Option<u64>)> {
+    let stat = nix::sys::statvfs::statvfs(path)?;
+    let block_size = stat.fragment_size();
+    Ok((
+        Some(stat.blocks_available() * block_size),
+        Some(stat.blocks() * block_size),
+    ))
+}
+
+/// Writes and removes a small temporary file in `dir` to confirm it's actually writable, not
+/// just present. A stale NFS mount or a directory gone read-only after a disk error can pass a
+/// simple existence check while failing every real write.
+///
+/// The filename is unique per call (pid + a random suffix) so that two concurrent diagnostics
+/// requests (e.g. two monitoring scrapers) don't race on the same path: otherwise request A's
+/// write-then-remove can complete entirely between request B's write and remove, making B's
+/// `remove_file` fail with `NotFound` even though the directory is perfectly writable.
+fn write_test(dir: &std::path::Path) -> std::io::Result<()> {
+    let mut suffix = [0u8; 8];
+    getrandom::getrandom(&mut suffix)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+    let path = dir.join(format!(
+        ".moonfire-nvr-diagnostics-write-test.{}.{}",
+        std::process::id(),
+        suffix.iter().map(|b| format!("{b:02x}")).collect::<String>()
+    ));
+    std::fs::write(&path, b"")?;
+    std::fs::remove_file(&path)
+}
@@ -0,0 +1,342 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2022 The Moonfire NVR Authors; see AUTHORS and LICENSE.txt.
+// SPDX-License-Identifier: GPL-v3.0-or-later WITH GPL-3.0-linking-exception.
+
+//! HTTP routing, shared response helpers, and the connection-accept loop for the web API.
+
+pub mod accept;
+mod auth;
+mod compress;
+mod diagnostics;
+mod session;
+mod users;
+
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use bytes::Bytes;
+use http::{Request, Response, StatusCode};
+use http_body_util::{combinators::BoxBody, BodyExt, Full};
+use hyper::body::Incoming;
+use hyper_util::rt::TokioIo;
+use serde::{de::DeserializeOwned, Serialize};
+
+pub use accept::{ConnCounts, ConnData, Listener};
+pub use auth::{ApiAuth, CookieSessionAuth, TrustedHeaderAuth, UnixPeerCredentialAuth};
+pub use compress::Config as CompressConfig;
+
+pub(crate) type ResponseBody = BoxBody<Bytes, std::convert::Infallible>;
+pub(crate) type ResponseResult = Result<Response<ResponseBody>, base::Error>;
+
+/// The identity and permissions a request is authenticated as. [`Caller::anonymous`] for
+/// unauthenticated requests, which still have (typically empty) permissions.
+#[derive(Clone)]
+pub struct Caller {
+    pub user: Option<db::User>,
+    pub permissions: db::Permissions,
+}
+
+impl Caller {
+    pub fn anonymous() -> Self {
+        Caller {
+            user: None,
+            permissions: db::Permissions::default(),
+        }
+    }
+}
+
+/// Top-level web service state: the database, and everything each handler needs to resolve a
+/// [`Caller`] and serve a request.
+pub struct Service {
+    db: Arc<db::Database<base::clock::RealClocks>>,
+    webauthn: crate::webauthn::WebauthnState,
+    conn_counts: Arc<ConnCounts>,
+    auth: Box<dyn ApiAuth>,
+    compress_config: compress::Config,
+}
+
+impl Service {
+    pub fn new(
+        db: Arc<db::Database<base::clock::RealClocks>>,
+        webauthn: crate::webauthn::WebauthnState,
+        auth: Box<dyn ApiAuth>,
+        compress_config: CompressConfig,
+    ) -> Self {
+        Service {
+            db,
+            webauthn,
+            conn_counts: Arc::new(ConnCounts::default()),
+            auth,
+            compress_config,
+        }
+    }
+
+    /// Dispatches a request to the handler for its path, resolving a [`Caller`] first except
+    /// for the handful of routes reachable before a session exists.
+    async fn route(&self, req: Request<Incoming>, conn: &ConnData) -> ResponseResult {
+        let path = req.uri().path().to_owned();
+        let segments: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+        match segments.as_slice() {
+            ["api", "login"] => return self.login(req, conn).await,
+            ["api", "login", "webauthn"] => return self.login_webauthn(req, conn).await,
+            ["api", "invite", "redeem"] => return self.redeem_invite(req).await,
+            _ => {}
+        }
+        let caller = self.auth.authenticate(conn, req.headers())?;
+        match segments.as_slice() {
+            ["api", "users"] => self.users(req, caller).await,
+            ["api", "users", id] => self.user(req, caller, parse_id(id)?).await,
+            ["api", "users", id, "totp"] => self.user_totp(req, caller, parse_id(id)?).await,
+            ["api", "users", id, "webauthn"] => {
+                self.user_webauthn(req, caller, parse_id(id)?).await
+            }
+            ["api", "diagnostics"] => self.diagnostics(req, caller).await,
+            _ => Ok(plain_response(StatusCode::NOT_FOUND, "no such route")),
+        }
+    }
+}
+
+fn parse_id(s: &str) -> Result<i32, base::Error> {
+    s.parse()
+        .map_err(|_| base::err!(InvalidArgument, msg("invalid id {s:?}")))
+}
+
+/// Builds a fixed-body response. Infallible, so handlers call it directly rather than through
+/// `?`.
+pub(crate) fn plain_response<B: Into<Bytes>>(
+    status: StatusCode,
+    body: B,
+) -> Response<ResponseBody> {
+    Response::builder()
+        .status(status)
+        .header(http::header::CONTENT_TYPE, "text/plain")
+        .body(
+            Full::new(body.into())
+                .map_err(|i: std::convert::Infallible| match i {})
+                .boxed(),
+        )
+        .expect("static response is valid")
+}
+
+impl Service {
+    /// Serializes `body` as the JSON response to the request `parts` came from, transparently
+    /// gzip/deflate-compressing it (see [`compress`]) when the `Accept-Encoding` header offers a
+    /// supported encoding and the serialized size clears the configured `min_bytes` (see
+    /// [`Service::new`]'s `compress_config` argument).
+    pub(crate) fn serve_json<T: Serialize>(
+        &self,
+        parts: &http::request::Parts,
+        body: &T,
+    ) -> ResponseResult {
+        let json = serde_json::to_vec(body)
+            .map_err(|e| base::err!(Internal, msg("failed to serialize response: {e}")))?;
+        let config = &self.compress_config;
+        let mut builder = Response::builder()
+            .status(StatusCode::OK)
+            .header(http::header::CONTENT_TYPE, "application/json")
+            .header(http::header::VARY, compress::VARY_HEADER_VALUE);
+        let encoding = compress::negotiate(
+            parts.headers.get(http::header::ACCEPT_ENCODING),
+            json.len(),
+            config,
+        );
+        let out = match encoding {
+            Some(encoding) => {
+                let compressed = compress::compress(&json, encoding, config.level)
+                    .map_err(|e| base::err!(Internal, msg("failed to compress response: {e}")))?;
+                builder = builder.header(
+                    http::header::CONTENT_ENCODING,
+                    encoding.content_encoding_header(),
+                );
+                compressed
+            }
+            None => json,
+        };
+        Ok(builder
+            .body(
+                Full::new(Bytes::from(out))
+                    .map_err(|i: std::convert::Infallible| match i {})
+                    .boxed(),
+            )
+            .expect("response is valid"))
+    }
+}
+
+/// Reads a request body fully into memory, returning the request's parts alongside it.
+pub(crate) async fn into_json_body(
+    req: Request<Incoming>,
+) -> Result<(http::request::Parts, Bytes), base::Error> {
+    let (parts, body) = req.into_parts();
+    let bytes = body
+        .collect()
+        .await
+        .map_err(|e| base::err!(InvalidArgument, msg("failed to read request body: {e}")))?
+        .to_bytes();
+    Ok((parts, bytes))
+}
+
+/// Parses a JSON request body into `T`.
+pub(crate) fn parse_json_body<T: DeserializeOwned>(b: &Bytes) -> Result<T, base::Error> {
+    serde_json::from_slice(b)
+        .map_err(|e| base::err!(InvalidArgument, msg("invalid JSON body: {e}")))
+}
+
+/// Requires a CSRF token matching the caller's session when the caller is authenticated via
+/// cookie (vs. e.g. a trusted-header backend, where there's no session to forge on behalf of).
+pub(crate) fn require_csrf_if_session(
+    caller: &Caller,
+    csrf: Option<[u8; 32]>,
+) -> Result<(), base::Error> {
+    let Some(user) = caller.user.as_ref() else {
+        return Ok(());
+    };
+    if !user.session_has_csrf_protection() {
+        return Ok(());
+    }
+    let csrf = csrf.ok_or_else(|| base::err!(Unauthenticated, msg("csrf token required")))?;
+    if !user.check_csrf(&csrf) {
+        base::bail!(Unauthenticated, msg("incorrect csrf token"));
+    }
+    Ok(())
+}
+
+/// Builds the success response for a freshly-created session: sets the `Set-Cookie` header
+/// with the encoded session id and the given flags, mirroring `cmds/login.rs`'s own encoding.
+pub(crate) fn session_response(
+    _parts: &http::request::Parts,
+    sid: [u8; 48],
+    flags: i32,
+) -> ResponseResult {
+    use db::auth::SessionFlag;
+    let mut encoded = [0u8; 64];
+    base64::encode_config_slice(sid, base64::STANDARD_NO_PAD, &mut encoded);
+    let encoded = std::str::from_utf8(&encoded[..]).expect("base64 is valid UTF-8");
+    let mut cookie = format!("s={encoded}; Path=/");
+    if flags & (SessionFlag::HttpOnly as i32) != 0 {
+        cookie.push_str("; HttpOnly");
+    }
+    if flags & (SessionFlag::Secure as i32) != 0 {
+        cookie.push_str("; Secure");
+    }
+    if flags & (SessionFlag::SameSiteStrict as i32) != 0 {
+        cookie.push_str("; SameSite=Strict");
+    } else if flags & (SessionFlag::SameSite as i32) != 0 {
+        cookie.push_str("; SameSite=Lax");
+    }
+    let mut resp = plain_response(StatusCode::NO_CONTENT, &b""[..]);
+    resp.headers_mut().insert(
+        http::header::SET_COOKIE,
+        http::HeaderValue::from_str(&cookie)
+            .map_err(|e| base::err!(Internal, msg("invalid cookie: {e}")))?,
+    );
+    Ok(resp)
+}
+
+/// Session cookie flags used for every session this process mints directly over HTTP (as
+/// opposed to `nvr login`, a trusted local admin tool that lets the operator pick flags
+/// explicitly): matches `nvr login`'s own default of
+/// `http-only,secure,same-site,same-site-strict`, the safest setting.
+pub(crate) fn default_session_flags() -> i32 {
+    use db::auth::SessionFlag;
+    SessionFlag::HttpOnly as i32
+        | SessionFlag::Secure as i32
+        | SessionFlag::SameSite as i32
+        | SessionFlag::SameSiteStrict as i32
+}
+
+fn error_response(e: base::Error) -> Response<ResponseBody> {
+    plain_response(e.http_status(), e.to_string())
+}
+
+/// Binds the configured listeners — adopting systemd socket-activated fds when present,
+/// falling back to binding `http_addr`/`unix_path` directly otherwise — and serves requests on
+/// them until `shutdown` resolves.
+///
+/// Tells the service manager `READY=1` once listening, pings its watchdog (if `WatchdogSec=`
+/// is configured) on a timer derived from `WATCHDOG_USEC`, and tells it `STOPPING=1` as soon as
+/// shutdown begins.
+pub async fn serve(
+    service: Arc<Service>,
+    http_addr: Option<SocketAddr>,
+    unix_path: Option<PathBuf>,
+    shutdown: impl std::future::Future<Output = ()> + Send + 'static,
+) -> std::io::Result<()> {
+    let mut listeners = Listener::from_systemd()?;
+    if listeners.is_empty() {
+        if let Some(addr) = http_addr {
+            listeners.push(Listener::Tcp(tokio::net::TcpListener::bind(addr).await?));
+        }
+        if let Some(ref p) = unix_path {
+            listeners.push(Listener::Unix(tokio::net::UnixListener::bind(p)?));
+        }
+    }
+
+    Listener::notify_ready()?;
+    let watchdog = tokio::spawn(watchdog_loop());
+
+    let mut accept_tasks = tokio::task::JoinSet::new();
+    for mut listener in listeners {
+        let service = service.clone();
+        accept_tasks.spawn(async move {
+            loop {
+                match listener.accept_counted(service.conn_counts.clone()).await {
+                    Ok(conn) => {
+                        let service = service.clone();
+                        tokio::spawn(serve_conn(service, conn));
+                    }
+                    Err(e) => {
+                        log::warn!("accept failed: {e}");
+                    }
+                }
+            }
+        });
+    }
+
+    shutdown.await;
+    Listener::notify_stopping()?;
+    watchdog.abort();
+    accept_tasks.abort_all();
+    Ok(())
+}
+
+/// Runs the `WATCHDOG_USEC`-derived ping loop, or returns immediately if this unit doesn't have
+/// `WatchdogSec=` configured (no `WATCHDOG_USEC` in the environment).
+async fn watchdog_loop() {
+    let Some(usec) = std::env::var("WATCHDOG_USEC")
+        .ok()
+        .and_then(|s| s.parse::<u64>().ok())
+    else {
+        return;
+    };
+    // Ping at twice the requested frequency, as systemd's own docs recommend, so a single
+    // missed tick doesn't trip the watchdog.
+    let period = std::time::Duration::from_micros(usec) / 2;
+    let mut interval = tokio::time::interval(period);
+    loop {
+        interval.tick().await;
+        if let Err(e) = Listener::notify_watchdog() {
+            log::warn!("sd_notify watchdog ping failed: {e}");
+        }
+    }
+}
+
+async fn serve_conn(service: Arc<Service>, conn: accept::Conn) {
+    let conn_data = *conn.data();
+    let io = TokioIo::new(conn);
+    let svc = hyper::service::service_fn(move |req: Request<Incoming>| {
+        let service = service.clone();
+        async move {
+            Ok::<_, std::convert::Infallible>(match service.route(req, &conn_data).await {
+                Ok(resp) => resp,
+                Err(e) => error_response(e),
+            })
+        }
+    });
+    if let Err(e) = hyper::server::conn::http1::Builder::new()
+        .serve_connection(io, svc)
+        .await
+    {
+        log::debug!("connection error: {e}");
+    }
+}
@@ -0,0 +1,159 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2022 The Moonfire NVR Authors; see AUTHORS and LICENSE.txt.
+// SPDX-License-Identifier: GPL-v3.0-or-later WITH GPL-3.0-linking-exception.
+
+//! Session creation: `/api/login`.
+
+use base::{bail, err};
+use http::{Method, Request, StatusCode};
+use hyper::body::Incoming;
+
+use crate::json;
+use crate::totp;
+
+use super::{
+    accept::ConnData, default_session_flags, into_json_body, parse_json_body, plain_response,
+    session_response, ResponseResult, Service,
+};
+
+impl Service {
+    /// Verifies a username/password pair (and, if the user has TOTP active, an accompanying
+    /// code) and starts a session. The HTTP-facing counterpart of `nvr login`, which bypasses
+    /// both for trusted local admins.
+    pub(super) async fn login(
+        &self,
+        req: Request<Incoming>,
+        conn: &ConnData,
+    ) -> ResponseResult {
+        let (parts, b) = into_json_body(req).await?;
+        let r: json::LoginRequest = parse_json_body(&b)?;
+        let now = self.db.clocks().realtime().sec;
+        let mut l = self.db.lock();
+        let user = l
+            .get_user(&r.username)
+            .ok_or_else(|| err!(Unauthenticated, msg("incorrect username or password")))?;
+        if user.config.disabled {
+            bail!(Unauthenticated, msg("incorrect username or password"));
+        }
+        if !user.check_password(r.password)? {
+            bail!(Unauthenticated, msg("incorrect username or password"));
+        }
+        let id = user.id;
+        let permissions = user.permissions.clone();
+        let mut accepted_totp_counter = None;
+        if let Some(ref secret) = user.config.totp_secret {
+            let code = r
+                .totp_code
+                .ok_or_else(|| err!(Unauthenticated, msg("TOTP code required")))?;
+            accepted_totp_counter = Some(
+                totp::verify(secret, code, now, user.config.totp_last_accepted_counter)
+                    .ok_or_else(|| err!(Unauthenticated, msg("incorrect TOTP code")))?,
+            );
+        }
+        if let Some(counter) = accepted_totp_counter {
+            let mut change = user.change();
+            change.config.totp_last_accepted_counter = Some(counter);
+            l.apply_user_change(change)?;
+        }
+        let creation = db::auth::Request {
+            when_sec: Some(now),
+            user_agent: parts
+                .headers
+                .get(http::header::USER_AGENT)
+                .map(|v| v.as_bytes().to_vec()),
+            addr: conn.client_addr.map(|a| a.ip().to_string().into_bytes()),
+        };
+        let (sid, flags) = l.make_session(creation, id, None, default_session_flags(), permissions)?;
+        session_response(&parts, sid, flags)
+    }
+
+    /// `/api/login/webauthn`: the passkey counterpart of [`Self::login`] — starts (`POST`) or
+    /// finishes (`PATCH`) an authentication ceremony against a user's registered credentials,
+    /// calling `make_session` on success just like the password path.
+    pub(super) async fn login_webauthn(
+        &self,
+        req: Request<Incoming>,
+        conn: &ConnData,
+    ) -> ResponseResult {
+        match *req.method() {
+            Method::POST => self.begin_webauthn_login(req).await,
+            Method::PATCH => self.finish_webauthn_login(req, conn).await,
+            _ => Ok(plain_response(
+                StatusCode::METHOD_NOT_ALLOWED,
+                "POST or PATCH expected",
+            )),
+        }
+    }
+
+    async fn begin_webauthn_login(&self, req: Request<Incoming>) -> ResponseResult {
+        let (parts, b) = into_json_body(req).await?;
+        let r: json::BeginWebauthnLogin = parse_json_body(&b)?;
+        let l = self.db.lock();
+        let user = l
+            .get_user(&r.username)
+            .ok_or_else(|| err!(Unauthenticated, msg("incorrect username")))?;
+        if user.config.disabled {
+            bail!(Unauthenticated, msg("incorrect username"));
+        }
+        if user.webauthn_credentials.is_empty() {
+            bail!(Unauthenticated, msg("user has no registered passkeys"));
+        }
+        let passkeys: Vec<_> = user
+            .webauthn_credentials
+            .iter()
+            .map(|c| c.passkey.clone())
+            .collect();
+        let (challenge, token) = self.webauthn.start_authentication(user.id, &passkeys)?;
+        self.serve_json(&parts, &json::BeginWebauthnLoginResponse { challenge, token })
+    }
+
+    async fn finish_webauthn_login(
+        &self,
+        req: Request<Incoming>,
+        conn: &ConnData,
+    ) -> ResponseResult {
+        let (parts, b) = into_json_body(req).await?;
+        let r: json::FinishWebauthnLogin = parse_json_body(&b)?;
+        let now = self.db.clocks().realtime().sec;
+        let (user_id, result) = self
+            .webauthn
+            .finish_authentication(&r.token, &r.credential)
+            .map_err(|e| err!(Unauthenticated, msg("invalid passkey assertion: {e}")))?;
+        let mut l = self.db.lock();
+        let user = l
+            .get_user_by_id_mut(user_id)
+            .ok_or_else(|| err!(Unauthenticated, msg("no such user")))?;
+        if user.config.disabled {
+            bail!(Unauthenticated, msg("incorrect username or password"));
+        }
+        let stored = user
+            .webauthn_credentials
+            .iter()
+            .find(|c| &c.id == result.cred_id())
+            .ok_or_else(|| err!(Unauthenticated, msg("no such passkey credential")))?;
+        // A strict increase is required; a counter that stayed the same or went backwards means
+        // the assertion was replayed or the authenticator was cloned. Authenticators that don't
+        // implement a counter always report 0, so that case alone isn't suspicious.
+        if stored.counter != 0 && result.counter() <= stored.counter {
+            bail!(
+                Unauthenticated,
+                msg("passkey signature counter did not increase; authenticator may be cloned")
+            );
+        }
+        let permissions = user.permissions.clone();
+        let mut change = user.change();
+        change.update_webauthn_credential_counter(result.cred_id(), result.counter())?;
+        l.apply_user_change(change)?;
+        let creation = db::auth::Request {
+            when_sec: Some(now),
+            user_agent: parts
+                .headers
+                .get(http::header::USER_AGENT)
+                .map(|v| v.as_bytes().to_vec()),
+            addr: conn.client_addr.map(|a| a.ip().to_string().into_bytes()),
+        };
+        let (sid, flags) =
+            l.make_session(creation, user_id, None, default_session_flags(), permissions)?;
+        session_response(&parts, sid, flags)
+    }
+}
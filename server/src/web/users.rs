@@ -3,17 +3,38 @@
 // SPDX-License-Identifier: GPL-v3.0-or-later WITH GPL-3.0-linking-exception.
 
 //! User management: `/api/users/*`.
+//!
+//! TOTP enrollment (`/api/users/<id>/totp`) lives here; see [`crate::totp`] for the RFC 6238
+//! code generation/verification this relies on. When a user has an active `totp_secret`, the
+//! password login handler (`Service::login`) additionally requires a valid code alongside the
+//! password before calling `make_session`.
+//!
+//! Passkey registration (`/api/users/<id>/webauthn`) also lives here, backed by
+//! [`crate::webauthn::WebauthnState`]; the unauthenticated login-time authentication ceremony
+//! is driven from the login endpoint, which on success calls `make_session` just as the
+//! password and TOTP paths do.
+//!
+//! `post_users` can also create a user in a "pending invite" state instead of setting a
+//! password inline; `/api/invite/redeem` (unauthenticated, handled by [`Service::redeem_invite`])
+//! lets that invitee set their own password and start a session, using [`crate::invite`] for the
+//! single-use, hashed-at-rest token.
 
 use base::{bail, err};
 use http::{Method, Request, StatusCode};
 
-use crate::json::{self, PutUsersResponse, UserSubset, UserWithId};
+use crate::invite;
+use crate::json::{self, PutUsersResponse, TotpEnrollResponse, UserSubset, UserWithId};
+use crate::totp;
 
 use super::{
-    into_json_body, parse_json_body, plain_response, require_csrf_if_session, serve_json, Caller,
-    ResponseResult, Service,
+    default_session_flags, into_json_body, parse_json_body, plain_response,
+    require_csrf_if_session, session_response, Caller, ResponseResult, Service,
 };
 
+/// `issuer` embedded in TOTP provisioning URIs, shown by authenticator apps next to the
+/// account name.
+const TOTP_ISSUER: &str = "Moonfire NVR";
+
 impl Service {
     pub(super) async fn users(
         &self,
@@ -30,6 +51,49 @@ impl Service {
         }
     }
 
+    /// `/api/invite/redeem` (unauthenticated): lets an invitee set their initial password and
+    /// start a session, given the single-use token an admin generated via `post_users`.
+    pub(super) async fn redeem_invite(
+        &self,
+        req: Request<hyper::body::Incoming>,
+    ) -> ResponseResult {
+        let (parts, b) = into_json_body(req).await?;
+        let r: json::RedeemInvite = parse_json_body(&b)?;
+        let hash = invite::hash(&r.token);
+        let now = self.db.clocks().realtime().sec;
+        let mut l = self.db.lock();
+        let id = l
+            .users_by_id()
+            .iter()
+            .find(|(_, u)| {
+                !u.config.disabled
+                    && matches!(
+                        &u.config.pending_invite,
+                        Some(p) if invite::hashes_match(&p.hash, &hash) && p.expires_at_sec > now
+                    )
+            })
+            .map(|(&id, _)| id)
+            .ok_or_else(|| err!(NotFound, msg("invite not found, expired, or already used")))?;
+        let user = l
+            .get_user_by_id_mut(id)
+            .ok_or_else(|| err!(NotFound, msg("can't find requested user")))?;
+        let mut change = user.change();
+        change.set_password(r.password.to_owned());
+        change.config.pending_invite = None;
+        let permissions = l.apply_user_change(change)?.permissions.clone();
+        let creation = db::auth::Request {
+            when_sec: Some(now),
+            user_agent: parts
+                .headers
+                .get(http::header::USER_AGENT)
+                .map(|v| v.as_bytes().to_vec()),
+            addr: None,
+        };
+        let (sid, flags) =
+            l.make_session(creation, id, None, default_session_flags(), permissions)?;
+        session_response(&parts, sid, flags)
+    }
+
     async fn get_users(
         &self,
         req: Request<hyper::body::Incoming>,
@@ -38,6 +102,7 @@ impl Service {
         if !caller.permissions.admin_users {
             bail!(Unauthenticated, msg("must have admin_users permission"));
         }
+        let (parts, _) = req.into_parts();
         let l = self.db.lock();
         let users = l
             .users_by_id()
@@ -47,7 +112,7 @@ impl Service {
                 user: UserSubset::from(user),
             })
             .collect();
-        serve_json(&req, &json::GetUsersResponse { users })
+        self.serve_json(&parts, &json::GetUsersResponse { users })
     }
 
     async fn post_users(
@@ -67,8 +132,16 @@ impl Service {
             .take()
             .ok_or_else(|| err!(InvalidArgument, msg("username must be specified")))?;
         let mut change = db::UserChange::add_user(username.to_owned());
+        let mut invite_token = None;
         if let Some(Some(pwd)) = r.user.password.take() {
             change.set_password(pwd.to_owned());
+        } else if r.invite.take().unwrap_or(false) {
+            let invite = invite::new();
+            change.config.pending_invite = Some(db::PendingInvite {
+                hash: invite.hash,
+                expires_at_sec: self.db.clocks().realtime().sec + invite::VALIDITY_SEC,
+            });
+            invite_token = Some(invite.token);
         }
         if let Some(preferences) = r.user.preferences.take() {
             change.config.preferences = preferences;
@@ -81,7 +154,13 @@ impl Service {
         }
         let mut l = self.db.lock();
         let user = l.apply_user_change(change)?;
-        serve_json(&parts, &PutUsersResponse { id: user.id })
+        self.serve_json(
+            &parts,
+            &PutUsersResponse {
+                id: user.id,
+                invite_token,
+            },
+        )
     }
 
     pub(super) async fn user(
@@ -108,12 +187,13 @@ impl Service {
         id: i32,
     ) -> ResponseResult {
         require_same_or_admin(&caller, id)?;
+        let (parts, _) = req.into_parts();
         let db = self.db.lock();
         let user = db
             .users_by_id()
             .get(&id)
             .ok_or_else(|| err!(NotFound, msg("can't find requested user")))?;
-        serve_json(&req, &UserSubset::from(user))
+        self.serve_json(&parts, &UserSubset::from(user))
     }
 
     async fn delete_user(
@@ -225,6 +305,209 @@ impl Service {
     }
 }
 
+impl Service {
+    /// `/api/users/<id>/webauthn`: passkey registration.
+    ///
+    /// Authentication (the unauthenticated half of the ceremony, run before a [`Caller`]
+    /// exists) is driven from the login endpoint rather than this module; on success it calls
+    /// the same `make_session` flow as password login.
+    pub(super) async fn user_webauthn(
+        &self,
+        req: Request<hyper::body::Incoming>,
+        caller: Caller,
+        id: i32,
+    ) -> ResponseResult {
+        match *req.method() {
+            Method::POST => self.begin_webauthn_registration(req, caller, id).await,
+            Method::PATCH => self.finish_webauthn_registration(req, caller, id).await,
+            Method::DELETE => self.delete_webauthn_credential(req, caller, id).await,
+            _ => Ok(plain_response(
+                StatusCode::METHOD_NOT_ALLOWED,
+                "POST, PATCH, or DELETE expected",
+            )),
+        }
+    }
+
+    /// Issues a registration challenge for a new passkey, excluding credential ids the user
+    /// already has on file.
+    async fn begin_webauthn_registration(
+        &self,
+        req: Request<hyper::body::Incoming>,
+        caller: Caller,
+        id: i32,
+    ) -> ResponseResult {
+        require_same_or_admin(&caller, id)?;
+        let (parts, b) = into_json_body(req).await?;
+        let r: json::BeginWebauthnRegistration = parse_json_body(&b)?;
+        require_csrf_if_session(&caller, r.csrf)?;
+        let db = self.db.lock();
+        let user = db
+            .users_by_id()
+            .get(&id)
+            .ok_or_else(|| err!(NotFound, msg("can't find requested user")))?;
+        let existing: Vec<_> = user.webauthn_credentials.iter().map(|c| c.id.clone()).collect();
+        let (challenge, token) = self.webauthn.start_registration(
+            id,
+            user.webauthn_user_uuid,
+            &user.username,
+            &existing,
+        )?;
+        self.serve_json(
+            &parts,
+            &json::BeginWebauthnRegistrationResponse { challenge, token },
+        )
+    }
+
+    /// Verifies the attestation for a ceremony started by [`Self::begin_webauthn_registration`]
+    /// and stores the resulting credential under the supplied nickname.
+    async fn finish_webauthn_registration(
+        &self,
+        req: Request<hyper::body::Incoming>,
+        caller: Caller,
+        id: i32,
+    ) -> ResponseResult {
+        require_same_or_admin(&caller, id)?;
+        let (_parts, b) = into_json_body(req).await?;
+        let r: json::FinishWebauthnRegistration = parse_json_body(&b)?;
+        require_csrf_if_session(&caller, r.csrf)?;
+        let passkey = self
+            .webauthn
+            .finish_registration(id, &r.token, &r.credential)
+            .map_err(|e| err!(InvalidArgument, msg("invalid passkey attestation: {e}")))?;
+        let mut db = self.db.lock();
+        let user = db
+            .get_user_by_id_mut(id)
+            .ok_or_else(|| err!(NotFound, msg("can't find requested user")))?;
+        let mut change = user.change();
+        change.add_webauthn_credential(r.nickname, passkey);
+        db.apply_user_change(change)?;
+        Ok(plain_response(StatusCode::NO_CONTENT, &b""[..]))
+    }
+
+    /// Removes a registered passkey, identified by its credential id.
+    async fn delete_webauthn_credential(
+        &self,
+        req: Request<hyper::body::Incoming>,
+        caller: Caller,
+        id: i32,
+    ) -> ResponseResult {
+        require_same_or_admin(&caller, id)?;
+        let (_parts, b) = into_json_body(req).await?;
+        let r: json::DeleteWebauthnCredential = parse_json_body(&b)?;
+        require_csrf_if_session(&caller, r.csrf)?;
+        let mut db = self.db.lock();
+        let user = db
+            .get_user_by_id_mut(id)
+            .ok_or_else(|| err!(NotFound, msg("can't find requested user")))?;
+        let mut change = user.change();
+        change.remove_webauthn_credential(&r.credential_id)?;
+        db.apply_user_change(change)?;
+        Ok(plain_response(StatusCode::NO_CONTENT, &b""[..]))
+    }
+
+    /// `/api/users/<id>/totp`: TOTP enrollment and teardown.
+    pub(super) async fn user_totp(
+        &self,
+        req: Request<hyper::body::Incoming>,
+        caller: Caller,
+        id: i32,
+    ) -> ResponseResult {
+        match *req.method() {
+            Method::POST => self.begin_totp_enroll(req, caller, id).await,
+            Method::PATCH => self.confirm_totp_enroll(req, caller, id).await,
+            Method::DELETE => self.disable_totp(req, caller, id).await,
+            _ => Ok(plain_response(
+                StatusCode::METHOD_NOT_ALLOWED,
+                "POST, PATCH, or DELETE expected",
+            )),
+        }
+    }
+
+    /// Generates and stores a pending secret, returning its `otpauth://` provisioning URI.
+    /// The secret isn't active until confirmed with a valid code via [`Self::confirm_totp_enroll`].
+    async fn begin_totp_enroll(
+        &self,
+        req: Request<hyper::body::Incoming>,
+        caller: Caller,
+        id: i32,
+    ) -> ResponseResult {
+        require_same_or_admin(&caller, id)?;
+        let (parts, b) = into_json_body(req).await?;
+        let r: json::BeginTotpEnroll = parse_json_body(&b)?;
+        require_csrf_if_session(&caller, r.csrf)?;
+        let secret = totp::generate_secret();
+        let mut db = self.db.lock();
+        let user = db
+            .get_user_by_id_mut(id)
+            .ok_or_else(|| err!(NotFound, msg("can't find requested user")))?;
+        let username = user.username.clone();
+        let mut change = user.change();
+        change.config.pending_totp_secret = Some(secret.to_vec());
+        db.apply_user_change(change)?;
+        self.serve_json(
+            &parts,
+            &TotpEnrollResponse {
+                provisioning_uri: totp::provisioning_uri(TOTP_ISSUER, &username, &secret),
+            },
+        )
+    }
+
+    /// Verifies one code against the pending secret, then activates it, replacing any
+    /// previously-active secret.
+    async fn confirm_totp_enroll(
+        &self,
+        req: Request<hyper::body::Incoming>,
+        caller: Caller,
+        id: i32,
+    ) -> ResponseResult {
+        require_same_or_admin(&caller, id)?;
+        let (_parts, b) = into_json_body(req).await?;
+        let r: json::ConfirmTotpEnroll = parse_json_body(&b)?;
+        require_csrf_if_session(&caller, r.csrf)?;
+        let mut db = self.db.lock();
+        let user = db
+            .get_user_by_id_mut(id)
+            .ok_or_else(|| err!(NotFound, msg("can't find requested user")))?;
+        let secret = user
+            .config
+            .pending_totp_secret
+            .clone()
+            .ok_or_else(|| err!(FailedPrecondition, msg("no pending TOTP enrollment")))?;
+        let now = self.db.clocks().realtime().sec;
+        let accepted_counter = totp::verify(&secret, r.code, now, None)
+            .ok_or_else(|| err!(InvalidArgument, msg("incorrect code")))?;
+        let mut change = user.change();
+        change.config.totp_secret = Some(secret);
+        change.config.totp_last_accepted_counter = Some(accepted_counter);
+        change.config.pending_totp_secret = None;
+        db.apply_user_change(change)?;
+        Ok(plain_response(StatusCode::NO_CONTENT, &b""[..]))
+    }
+
+    /// Disables TOTP for this user, mirroring the admin "remove 2FA" capability.
+    async fn disable_totp(
+        &self,
+        req: Request<hyper::body::Incoming>,
+        caller: Caller,
+        id: i32,
+    ) -> ResponseResult {
+        require_same_or_admin(&caller, id)?;
+        let (_parts, b) = into_json_body(req).await?;
+        let r: json::DisableTotp = parse_json_body(&b)?;
+        require_csrf_if_session(&caller, r.csrf)?;
+        let mut db = self.db.lock();
+        let user = db
+            .get_user_by_id_mut(id)
+            .ok_or_else(|| err!(NotFound, msg("can't find requested user")))?;
+        let mut change = user.change();
+        change.config.totp_secret = None;
+        change.config.totp_last_accepted_counter = None;
+        change.config.pending_totp_secret = None;
+        db.apply_user_change(change)?;
+        Ok(plain_response(StatusCode::NO_CONTENT, &b""[..]))
+    }
+}
+
 fn require_same_or_admin(caller: &Caller, id: i32) -> Result<(), base::Error> {
     if caller.user.as_ref().map(|u| u.id) != Some(id) && !caller.permissions.admin_users {
         bail!(
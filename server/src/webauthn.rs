@@ -0,0 +1,163 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2022 The Moonfire NVR Authors; see AUTHORS and LICENSE.txt.
+// SPDX-License-Identifier: GPL-v3.0-or-later WITH GPL-3.0-linking-exception.
+
+//! WebAuthn/FIDO2 passkey registration and authentication.
+//!
+//! `webauthn-rs` owns the ceremony state machine (challenge generation, attestation/assertion
+//! verification); this module's job is holding that state across the two-request ceremony and
+//! returning the verified results. Storage is done by `web::users`, which stores one
+//! [`Credential`] per row of the `user_webauthn_credentials` table, and the login path in
+//! `web::session` turns a verified assertion into a session for a [`crate::web::Caller`].
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use url::Url;
+use webauthn_rs::prelude::*;
+
+/// How long a started-but-unfinished ceremony stays in [`WebauthnState`]'s maps. Both
+/// `start_registration` and `start_authentication` are reachable without an established
+/// session (the latter by design, before the client has proven which passkey it holds), so a
+/// client that starts ceremonies and never finishes them would otherwise leak an entry per
+/// request with no bound. Pruned lazily on the next `start_*` call rather than on a timer, since
+/// there's nothing else driving periodic work in this struct.
+const CEREMONY_TTL: Duration = Duration::from_secs(5 * 60);
+
+/// Drops entries older than [`CEREMONY_TTL`] from a ceremony map.
+fn prune_expired<T>(ceremonies: &mut HashMap<String, (Instant, i32, T)>) {
+    ceremonies.retain(|_, (started, _, _)| started.elapsed() < CEREMONY_TTL);
+}
+
+/// A stored, user-named passkey credential.
+///
+/// Maps 1:1 to a row of `user_webauthn_credentials`: `id` and `passkey` are serialized as
+/// opaque blobs, `nickname` is operator-supplied at registration time to tell credentials apart
+/// in the UI. `counter` mirrors the authenticator's last-accepted signature counter as its own
+/// column (rather than requiring every reader to pick it back out of the `passkey` blob) so
+/// callers can compare it against a fresh assertion's counter before accepting one, per
+/// [`WebauthnState::finish_authentication`].
+pub struct Credential {
+    pub id: CredentialID,
+    pub nickname: String,
+    pub passkey: Passkey,
+    pub counter: u32,
+}
+
+/// Registration/authentication ceremony state, keyed by a random, single-use session token
+/// returned to the client alongside the challenge. Entries are removed on completion (success
+/// or failure) and are otherwise expired after [`CEREMONY_TTL`].
+pub struct WebauthnState {
+    webauthn: Webauthn,
+    registrations: Mutex<HashMap<String, (Instant, i32, PasskeyRegistration)>>,
+    authentications: Mutex<HashMap<String, (Instant, i32, PasskeyAuthentication)>>,
+}
+
+impl WebauthnState {
+    /// Builds the ceremony verifier for a relying party served at `origin` (e.g.
+    /// `https://nvr.example.com`), identified to authenticators as `rp_id` (typically the
+    /// origin's domain).
+    pub fn new(rp_id: &str, origin: &Url) -> Result<Self, WebauthnError> {
+        let webauthn = WebauthnBuilder::new(rp_id, origin)?
+            .rp_name("Moonfire NVR")
+            .build()?;
+        Ok(Self {
+            webauthn,
+            registrations: Mutex::new(HashMap::new()),
+            authentications: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Starts a registration ceremony for `user_id`, excluding any `existing` credential ids so
+    /// the authenticator doesn't offer to re-register one already on file. Returns the
+    /// challenge to send to the client and an opaque token identifying this ceremony.
+    pub fn start_registration(
+        &self,
+        user_id: i32,
+        user_uuid: Uuid,
+        username: &str,
+        existing: &[CredentialID],
+    ) -> Result<(CreationChallengeResponse, String), WebauthnError> {
+        let (ccr, reg_state) = self.webauthn.start_passkey_registration(
+            user_uuid,
+            username,
+            username,
+            Some(existing.to_vec()),
+        )?;
+        let token = new_ceremony_token();
+        let mut registrations = self.registrations.lock().unwrap();
+        prune_expired(&mut registrations);
+        registrations.insert(token.clone(), (Instant::now(), user_id, reg_state));
+        Ok((ccr, token))
+    }
+
+    /// Verifies the attestation returned by the client, completing the ceremony started by
+    /// [`Self::start_registration`] with the same `token`. Returns the credential to persist.
+    pub fn finish_registration(
+        &self,
+        user_id: i32,
+        token: &str,
+        response: &RegisterPublicKeyCredential,
+    ) -> Result<Passkey, WebauthnError> {
+        let (started, expected_user_id, reg_state) = self
+            .registrations
+            .lock()
+            .unwrap()
+            .remove(token)
+            .ok_or(WebauthnError::ChallengeNotFound)?;
+        if started.elapsed() >= CEREMONY_TTL || expected_user_id != user_id {
+            return Err(WebauthnError::ChallengeNotFound);
+        }
+        self.webauthn
+            .finish_passkey_registration(response, &reg_state)
+    }
+
+    /// Starts an authentication ceremony for `user_id` against its candidate passkeys
+    /// (typically all credentials registered for the username the client supplied at the
+    /// login prompt, before any signature has been checked).
+    pub fn start_authentication(
+        &self,
+        user_id: i32,
+        credentials: &[Passkey],
+    ) -> Result<(RequestChallengeResponse, String), WebauthnError> {
+        let (rcr, auth_state) = self.webauthn.start_passkey_authentication(credentials)?;
+        let token = new_ceremony_token();
+        let mut authentications = self.authentications.lock().unwrap();
+        prune_expired(&mut authentications);
+        authentications.insert(token.clone(), (Instant::now(), user_id, auth_state));
+        Ok((rcr, token))
+    }
+
+    /// Verifies the assertion returned by the client, completing the ceremony started by
+    /// [`Self::start_authentication`] with the same `token`. On success, returns the id of the
+    /// user who started the ceremony alongside the authentication result; the caller must check
+    /// [`AuthenticationResult::counter`] against the credential's last-known counter and reject
+    /// (as a cloned authenticator) if it hasn't strictly increased, then persist the new
+    /// counter.
+    pub fn finish_authentication(
+        &self,
+        token: &str,
+        response: &PublicKeyCredential,
+    ) -> Result<(i32, AuthenticationResult), WebauthnError> {
+        let (started, user_id, auth_state) = self
+            .authentications
+            .lock()
+            .unwrap()
+            .remove(token)
+            .ok_or(WebauthnError::ChallengeNotFound)?;
+        if started.elapsed() >= CEREMONY_TTL {
+            return Err(WebauthnError::ChallengeNotFound);
+        }
+        let result = self
+            .webauthn
+            .finish_passkey_authentication(response, &auth_state)?;
+        Ok((user_id, result))
+    }
+}
+
+fn new_ceremony_token() -> String {
+    let mut raw = [0u8; 18];
+    getrandom::getrandom(&mut raw).expect("getrandom failed");
+    base64::encode_config(raw, base64::URL_SAFE_NO_PAD)
+}